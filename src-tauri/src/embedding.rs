@@ -0,0 +1,145 @@
+use anyhow::{anyhow, Result};
+use candle_core::{Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::models::bert::{BertModel, Config as BertConfig, DTYPE};
+use tokenizers::{PaddingParams, PaddingStrategy, Tokenizer};
+
+/// A local sentence-embedding model (a BERT-family encoder, loaded from
+/// safetensors) used to turn text into vectors for retrieval. This mirrors
+/// the chat `LLMAgent` in spirit - it owns its own `Tokenizer`/`Device` - but
+/// is kept separate since embedding and generation are unrelated concerns
+/// with different model architectures.
+pub struct EmbeddingModel {
+    model: BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl EmbeddingModel {
+    pub fn load(model_path: &str, tokenizer_path: &str, config_path: &str, device: &Device) -> Result<Self> {
+        let config = std::fs::read_to_string(config_path)?;
+        let config: BertConfig = serde_json::from_str(&config)?;
+
+        let mut tokenizer = Tokenizer::from_file(tokenizer_path).map_err(|e| anyhow!("Tokenizer error: {}", e))?;
+        let padding = PaddingParams { strategy: PaddingStrategy::BatchLongest, ..Default::default() };
+        tokenizer.with_padding(Some(padding));
+
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[model_path], DTYPE, device)? };
+        let model = BertModel::load(vb, &config)?;
+
+        Ok(Self { model, tokenizer, device: device.clone() })
+    }
+
+    /// Embeds a batch of texts, returning one mean-pooled, L2-normalized
+    /// vector per input so that cosine similarity reduces to a dot product.
+    pub fn embed(&self, texts: Vec<String>) -> Result<Vec<Vec<f32>>> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts, true)
+            .map_err(|e| anyhow!("Tokenization failed: {}", e))?;
+
+        let token_ids = encodings
+            .iter()
+            .map(|enc| Tensor::new(enc.get_ids(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+
+        let attention_mask = encodings
+            .iter()
+            .map(|enc| Tensor::new(enc.get_attention_mask(), &self.device))
+            .collect::<candle_core::Result<Vec<_>>>()?;
+        let attention_mask = Tensor::stack(&attention_mask, 0)?;
+
+        let embeddings = self.model.forward(&token_ids, &token_type_ids, Some(&attention_mask))?;
+
+        // Mean-pool over the sequence dimension, counting only the real
+        // (non-padding) tokens each row has - otherwise padding positions,
+        // which the padder adds whenever a batch's texts have different
+        // lengths, would drag every pooled vector towards the embedding of
+        // the pad token.
+        let mask = attention_mask.to_dtype(embeddings.dtype())?.unsqueeze(2)?;
+        let mask = mask.broadcast_as(embeddings.shape())?;
+        let summed = (&embeddings * &mask)?.sum(1)?;
+        let counts = mask.sum(1)?;
+        let embeddings = (summed / counts)?;
+        let norms = embeddings.sqr()?.sum_keepdim(1)?.sqrt()?;
+        let embeddings = embeddings.broadcast_div(&norms)?;
+
+        embeddings.to_vec2::<f32>().map_err(|e| anyhow!("Failed to read embeddings: {}", e))
+    }
+}
+
+/// A flat, in-process index of (text, embedding) pairs. Good enough for a
+/// single conversation's worth of indexed documents; an HNSW graph would be
+/// worth it once the corpus is too large to scan linearly on every query.
+pub struct DocumentStore {
+    entries: Vec<(String, Vec<f32>)>,
+}
+
+impl DocumentStore {
+    pub fn new() -> Self {
+        Self { entries: Vec::new() }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn insert(&mut self, text: String, embedding: Vec<f32>) {
+        self.entries.push((text, embedding));
+    }
+
+    /// Returns the text of the `k` entries closest to `query` by cosine
+    /// similarity, most similar first.
+    pub fn top_k(&self, query: &[f32], k: usize) -> Vec<String> {
+        let mut scored: Vec<(f32, &str)> = self
+            .entries
+            .iter()
+            .map(|(text, embedding)| (cosine_similarity(query, embedding), text.as_str()))
+            .collect();
+        scored.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap_or(std::cmp::Ordering::Equal));
+        scored.into_iter().take(k).map(|(_, text)| text.to_string()).collect()
+    }
+}
+
+fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        0.0
+    } else {
+        dot / (norm_a * norm_b)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cosine_similarity_table() {
+        let cases: [(&[f32], &[f32], f32); 4] = [
+            (&[1.0, 0.0], &[1.0, 0.0], 1.0),
+            (&[1.0, 0.0], &[0.0, 1.0], 0.0),
+            (&[1.0, 0.0], &[-1.0, 0.0], -1.0),
+            (&[0.0, 0.0], &[1.0, 0.0], 0.0),
+        ];
+        for (a, b, expected) in cases {
+            let got = cosine_similarity(a, b);
+            assert!((got - expected).abs() < 1e-6, "cosine_similarity({:?}, {:?}) = {} (expected {})", a, b, got, expected);
+        }
+    }
+
+    #[test]
+    fn top_k_ranks_by_similarity() {
+        let mut store = DocumentStore::new();
+        store.insert("unrelated".to_string(), vec![0.0, 1.0]);
+        store.insert("exact match".to_string(), vec![1.0, 0.0]);
+        store.insert("somewhat related".to_string(), vec![0.7, 0.7]);
+
+        let hits = store.top_k(&[1.0, 0.0], 2);
+        assert_eq!(hits, vec!["exact match".to_string(), "somewhat related".to_string()]);
+    }
+}
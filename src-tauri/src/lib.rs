@@ -1,16 +1,82 @@
 use std::sync::Arc;
+use std::collections::HashSet;
 use std::env;
 use std::path::Path;
 use serde::{Deserialize, Serialize};
-use tauri::State;
+use tauri::{AppHandle, Emitter, State};
 use tokio::sync::Mutex;
 use anyhow::{Result, anyhow};
-use candle_core::{Device, Tensor, DType};
-use candle_transformers::models::llama::{Llama, Config as LlamaConfig, Cache};
-use candle_nn::VarBuilder;
+use candle_core::{Device, Tensor};
+use candle_transformers::models::quantized_llama::ModelWeights as QLlama;
+use candle_transformers::models::quantized_qwen2::ModelWeights as QQwen2;
+use candle_transformers::models::quantized_qwen3::ModelWeights as QQwen3;
+use candle_transformers::models::quantized_gemma3::ModelWeights as QGemma;
+use candle_transformers::models::quantized_phi3::ModelWeights as QPhi3;
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::utils::apply_repeat_penalty;
 use candle_core::quantized::gguf_file;
 use tokenizers::Tokenizer;
-use std::collections::HashMap;
+use minijinja::Environment;
+use serde_json::Value as JsonValue;
+
+mod embedding;
+use embedding::{DocumentStore, EmbeddingModel};
+
+/// The candle model behind a loaded GGUF file.
+///
+/// `general.architecture` in the GGUF metadata tells us which of candle's
+/// quantized model implementations to construct; each has its own
+/// attention/MLP layout and weight names, so there is no single loader that
+/// works for all of them. `quantized_qwen3`/`quantized_phi3` are dense-only,
+/// so mixture-of-experts GGUFs (e.g. Qwen3-30B-A3B, Phi-3.5-MoE) aren't
+/// supported yet - `from_gguf` rejects them explicitly rather than loading a
+/// dense model against an MoE tensor layout.
+enum LoadedModel {
+    Llama(QLlama),
+    Qwen2(QQwen2),
+    Qwen3(QQwen3),
+    Gemma(QGemma),
+    Phi3(QPhi3),
+}
+
+impl LoadedModel {
+    fn forward(&mut self, input: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let logits = match self {
+            LoadedModel::Llama(m) => m.forward(input, index_pos)?,
+            LoadedModel::Qwen2(m) => m.forward(input, index_pos)?,
+            LoadedModel::Qwen3(m) => m.forward(input, index_pos)?,
+            LoadedModel::Gemma(m) => m.forward(input, index_pos)?,
+            LoadedModel::Phi3(m) => m.forward(input, index_pos)?,
+        };
+        Ok(logits)
+    }
+
+    fn from_gguf(content: gguf_file::Content, file: &mut std::fs::File, device: &Device) -> Result<Self> {
+        let architecture = content
+            .metadata
+            .get("general.architecture")
+            .and_then(|v| v.to_string().ok())
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "llama".to_string());
+
+        println!("Detected model architecture: {}", architecture);
+
+        let model = match architecture.as_str() {
+            "qwen2" => LoadedModel::Qwen2(QQwen2::from_gguf(content, file, device)?),
+            "qwen3" => LoadedModel::Qwen3(QQwen3::from_gguf(content, file, device)?),
+            "gemma" | "gemma2" | "gemma3" => LoadedModel::Gemma(QGemma::from_gguf(content, file, device)?),
+            "phi3" | "phi3.5" => LoadedModel::Phi3(QPhi3::from_gguf(content, file, device)?),
+            "qwen3moe" | "phimoe" => {
+                return Err(anyhow!(
+                    "'{}' is a mixture-of-experts architecture - candle's quantized loaders here are dense-only and would misread its tensor layout. MoE GGUFs aren't supported yet.",
+                    architecture
+                ));
+            }
+            _ => LoadedModel::Llama(QLlama::from_gguf(content, file, device)?),
+        };
+        Ok(model)
+    }
+}
 
 #[derive(Debug, Clone)]
 struct ChatMessage {
@@ -18,17 +84,151 @@ struct ChatMessage {
     content: String,
 }
 
+/// The subset of `tokenizer_config.json` we care about: the Jinja-style chat
+/// template the model was trained with, and its EOS token. Modern instruct
+/// models (Qwen3, DeepSeek-R1-Qwen3, ...) use `<|im_start|>`/`<|im_end|>`-style
+/// special tokens rather than the Llama `<s>`/`</s>` pair, so both of these
+/// have to come from the model's own config rather than being assumed.
+#[derive(Debug, Clone, Deserialize)]
+struct TokenizerConfigFile {
+    chat_template: Option<String>,
+    #[serde(default)]
+    eos_token: Option<JsonValue>,
+}
+
+/// `eos_token` (and similar fields) can be a plain string or an
+/// `{"content": "...", ...}` `AddedToken` object depending on the tokenizer
+/// version that produced the config.
+fn token_value_to_string(value: &JsonValue) -> Option<String> {
+    match value {
+        JsonValue::String(s) => Some(s.clone()),
+        JsonValue::Object(map) => map.get("content").and_then(|v| v.as_str()).map(|s| s.to_string()),
+        _ => None,
+    }
+}
+
+/// `generation_config.json`'s `eos_token_id` can be a single number or a
+/// list of them, depending on how many stop tokens the model was trained
+/// with.
+fn json_value_to_u32s(value: &JsonValue) -> Vec<u32> {
+    match value {
+        JsonValue::Number(n) => n.as_u64().map(|v| vec![v as u32]).unwrap_or_default(),
+        JsonValue::Array(items) => items.iter().filter_map(|v| v.as_u64()).map(|v| v as u32).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// User-tunable knobs for the sampling step of generation.
+///
+/// `temperature == 0.0` selects greedy argmax decoding; anything above that
+/// samples from the top-k/top-p distribution via `LogitsProcessor`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct GenerationConfig {
+    temperature: f64,
+    top_p: f64,
+    top_k: usize,
+    seed: u64,
+    repeat_penalty: f32,
+    repeat_last_n: usize,
+    max_new_tokens: usize,
+}
+
+impl Default for GenerationConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            top_p: 0.9,
+            top_k: 40,
+            seed: 299792458,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+            max_new_tokens: 256,
+        }
+    }
+}
+
+/// Incrementally decodes a token stream into UTF-8 text.
+///
+/// Candle's tokenizers decode a slice of token ids at a time, so a single new
+/// token can land in the middle of a multi-byte character. This mirrors
+/// candle's `TokenOutputStream` helper: it keeps re-decoding from `prev_index`
+/// and only yields the new suffix once the decode is stable (longer than the
+/// previous one and not ending in the replacement character U+FFFD).
+struct TokenOutputStream {
+    tokenizer: Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+    current_index: usize,
+}
+
+impl TokenOutputStream {
+    fn new(tokenizer: Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: Vec::new(),
+            prev_index: 0,
+            current_index: 0,
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(|e| anyhow!("Decoding failed: {}", e))
+    }
+
+    /// Pushes `token` and returns the newly-revealed text, if any.
+    fn next_token(&mut self, token: u32) -> Result<Option<String>> {
+        let prev_text = if self.current_index == 0 {
+            String::new()
+        } else {
+            self.decode(&self.tokens[self.prev_index..self.current_index])?
+        };
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if Self::is_new_text_ready(&prev_text, &text) {
+            self.prev_index = self.current_index;
+            self.current_index = self.tokens.len();
+            Ok(Some(text[prev_text.len()..].to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// A decode is safe to emit once it is longer than the previously
+    /// emitted text and doesn't end mid-character (the replacement
+    /// character marks a token boundary that split a multi-byte UTF-8
+    /// sequence).
+    fn is_new_text_ready(prev_text: &str, text: &str) -> bool {
+        text.len() > prev_text.len() && !text.ends_with('\u{FFFD}')
+    }
+}
+
 struct LLMAgent {
     model_path: Option<String>,
     conversation: Vec<ChatMessage>,
     system_prompt: String,
     is_initialized: bool,
     model_name: String,
-    model: Option<Llama>,
+    model: Option<LoadedModel>,
     tokenizer: Option<Tokenizer>,
     device: Device,
-    cache: Option<Cache>,
-    config: Option<LlamaConfig>,
+    generation_config: GenerationConfig,
+    /// Number of tokens already fed through the model's KV cache. The next
+    /// `forward` call must start at this position so rotary embeddings and
+    /// cached keys/values line up with the new tokens being appended.
+    index_pos: usize,
+    /// The exact token sequence the KV cache currently holds (prompt tokens
+    /// from every turn processed so far, plus every generated token), in
+    /// cache order. Since every turn re-renders the *whole* conversation,
+    /// this lets the next turn find how much of its newly-rendered prompt is
+    /// already cached (the common prefix) and forward only the new suffix,
+    /// instead of reprocessing or reloading everything.
+    cached_tokens: Vec<u32>,
+    chat_template: Option<String>,
+    eos_token_ids: Vec<u32>,
+    embedding_model: Option<EmbeddingModel>,
+    document_store: DocumentStore,
 }
 
 impl LLMAgent {
@@ -42,8 +242,25 @@ impl LLMAgent {
             model: None,
             tokenizer: None,
             device: Device::Cpu,
-            cache: None,
-            config: None,
+            generation_config: GenerationConfig::default(),
+            index_pos: 0,
+            cached_tokens: Vec::new(),
+            chat_template: None,
+            eos_token_ids: Vec::new(),
+            embedding_model: None,
+            document_store: DocumentStore::new(),
+        }
+    }
+
+    fn update_generation_config(&mut self, config: GenerationConfig) {
+        self.generation_config = config;
+    }
+
+    fn update_system_prompt(&mut self, prompt: String) {
+        self.system_prompt = prompt.clone();
+        match self.conversation.first_mut() {
+            Some(first) if first.role == "system" => first.content = prompt,
+            _ => self.conversation.insert(0, ChatMessage { role: "system".to_string(), content: prompt }),
         }
     }
 
@@ -62,73 +279,83 @@ impl LLMAgent {
             .unwrap_or("Unknown");
         self.model_name = model_name.to_string();
 
-        // Load GGUF model
+        // Load the GGUF model, keeping weights in their quantized form. Candle
+        // dequantizes per-op during the forward pass instead of expanding
+        // everything to F32 up front, which is what actually lets a
+        // multi-gigabyte Q4_K_M/Q8_0 file fit in realistic RAM/VRAM.
         println!("Loading GGUF model from: {}", model_path);
-        let mut file = std::fs::File::open(model_path)?;
-        let content = gguf_file::Content::read(&mut file)?;
-        
-        // Extract model weights
-        let mut tensors: HashMap<String, Tensor> = HashMap::new();
-        for (name, tensor) in content.tensor_infos.iter() {
-            let tensor_data = content.tensor_data(name)?;
-            let shape: Vec<usize> = tensor.shape.iter().map(|&x| x as usize).collect();
-            let tensor = match tensor.ggml_dtype {
-                candle_core::quantized::GgmlDType::F32 => {
-                    let data: &[f32] = bytemuck::cast_slice(&tensor_data);
-                    Tensor::from_slice(data, &shape, &self.device)?
-                }
-                candle_core::quantized::GgmlDType::F16 => {
-                    let data: &[half::f16] = bytemuck::cast_slice(&tensor_data);
-                    let data: Vec<f32> = data.iter().map(|x| x.to_f32()).collect();
-                    Tensor::from_slice(&data, &shape, &self.device)?
-                }
-                _ => {
-                    return Err(anyhow!("Unsupported tensor dtype: {:?}", tensor.ggml_dtype));
-                }
-            };
-            tensors.insert(name.clone(), tensor);
-        }
-
-        // Create VarBuilder from tensors
-        let vb = VarBuilder::from_tensors(tensors, DType::F32, &self.device);
-
-        // Create config from GGUF metadata
-        let metadata = &content.metadata;
-        let config = LlamaConfig {
-            hidden_size: metadata.get("llama.embedding_length").and_then(|v| v.to_u32()).unwrap_or(4096) as usize,
-            intermediate_size: metadata.get("llama.feed_forward_length").and_then(|v| v.to_u32()).unwrap_or(11008) as usize,
-            vocab_size: metadata.get("llama.vocab_size").and_then(|v| v.to_u32()).unwrap_or(32000) as usize,
-            num_hidden_layers: metadata.get("llama.block_count").and_then(|v| v.to_u32()).unwrap_or(32) as usize,
-            num_attention_heads: metadata.get("llama.attention.head_count").and_then(|v| v.to_u32()).unwrap_or(32) as usize,
-            num_key_value_heads: metadata.get("llama.attention.head_count_kv").and_then(|v| v.to_u32()).unwrap_or(32) as usize,
-            rms_norm_eps: metadata.get("llama.attention.layer_norm_rms_epsilon").and_then(|v| v.to_f32()).unwrap_or(1e-6),
-            rope_theta: metadata.get("llama.rope.freq_base").and_then(|v| v.to_f32()).unwrap_or(10000.0),
-            max_position_embeddings: metadata.get("llama.context_length").and_then(|v| v.to_u32()).unwrap_or(2048) as usize,
-            use_flash_attn: false,
-        };
-
-        // Load the model
-        println!("Creating Llama model with config: {:?}", config);
-        let model = Llama::load(&vb, &config)?;
-        self.model = Some(model);
-        self.config = Some(config.clone());
-
-        // Initialize cache
-        self.cache = Some(Cache::new(true, DType::F32, &config, &self.device)?);
+        self.model = Some(Self::load_quantized_model(model_path, &self.device)?);
 
         // Try to load tokenizer
         self.tokenizer = self.load_tokenizer().await.ok();
-        
+
         if self.tokenizer.is_none() {
             return Err(anyhow!("Could not load tokenizer. Please ensure tokenizer.json is in the same directory as your model file."));
         }
 
+        // Pull the chat template and the full set of stop tokens out of
+        // tokenizer_config.json/generation_config.json so prompts and stop
+        // conditions match what the model was trained on. A single
+        // `eos_token` isn't enough for instruct models like Qwen3/
+        // DeepSeek-R1-Qwen3, which stop on `<|im_end|>` while listing
+        // `<|endoftext|>` (or a list of ids) as their "real" EOS in
+        // generation_config.json - missing either one risks replies that
+        // never terminate.
+        let mut eos_token_ids: HashSet<u32> = HashSet::new();
+        if let Some(tokenizer_config) = self.load_tokenizer_config() {
+            self.chat_template = tokenizer_config.chat_template;
+            if let Some(id) = tokenizer_config
+                .eos_token
+                .as_ref()
+                .and_then(token_value_to_string)
+                .and_then(|text| self.tokenizer.as_ref().and_then(|t| t.token_to_id(&text)))
+            {
+                eos_token_ids.insert(id);
+            }
+        }
+        if let Some(generation_config) = self.load_generation_config() {
+            if let Some(value) = generation_config.get("eos_token_id") {
+                eos_token_ids.extend(json_value_to_u32s(value));
+            }
+        }
+        if let Some(tokenizer) = &self.tokenizer {
+            for special in ["<|im_end|>", "<|endoftext|>"] {
+                if let Some(id) = tokenizer.token_to_id(special) {
+                    eos_token_ids.insert(id);
+                }
+            }
+        }
+        self.eos_token_ids = eos_token_ids.into_iter().collect();
+        if self.eos_token_ids.is_empty() {
+            // No tokenizer_config.json/generation_config.json (or no usable
+            // EOS ids in either) - fall back to the common Llama-family EOS
+            // ids.
+            self.eos_token_ids = vec![2, 0];
+        }
+
+        // The embedding model is optional - retrieval just stays disabled if
+        // it isn't configured or fails to load.
+        match (env::var("EMBEDDING_MODEL_PATH"), env::var("EMBEDDING_TOKENIZER_PATH"), env::var("EMBEDDING_CONFIG_PATH")) {
+            (Ok(model_path), Ok(tokenizer_path), Ok(config_path)) => {
+                match EmbeddingModel::load(&model_path, &tokenizer_path, &config_path, &self.device) {
+                    Ok(embedder) => {
+                        println!("Loaded embedding model from: {}", model_path);
+                        self.embedding_model = Some(embedder);
+                    }
+                    Err(e) => println!("Could not load embedding model, retrieval disabled: {}", e),
+                }
+            }
+            _ => println!("EMBEDDING_MODEL_PATH/EMBEDDING_TOKENIZER_PATH/EMBEDDING_CONFIG_PATH not set, retrieval disabled"),
+        }
+
         // Initialize conversation with system prompt
         self.conversation.clear();
         self.conversation.push(ChatMessage {
             role: "system".to_string(),
             content: self.system_prompt.clone(),
         });
+        self.index_pos = 0;
+        self.cached_tokens.clear();
 
         self.is_initialized = true;
         Ok(format!("Successfully loaded GGUF model: {} with real inference capability!", model_name))
@@ -155,11 +382,121 @@ impl LLMAgent {
         Err(anyhow!("Tokenizer file not found"))
     }
 
-    async fn send_message(&mut self, message: &str) -> Result<String> {
+    fn load_tokenizer_config(&self) -> Option<TokenizerConfigFile> {
+        let model_path = self.model_path.as_ref()?;
+        let model_dir = Path::new(model_path).parent().unwrap_or(Path::new("."));
+        let path = model_dir.join("tokenizer_config.json");
+        if !path.exists() {
+            return None;
+        }
+        println!("Loading tokenizer config from: {:?}", path);
+        let data = std::fs::read_to_string(&path).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    /// `generation_config.json` is where HF-style checkpoints usually list
+    /// the model's real `eos_token_id` (a single id or a list of them),
+    /// separately from `tokenizer_config.json`'s `eos_token`.
+    fn load_generation_config(&self) -> Option<JsonValue> {
+        let model_path = self.model_path.as_ref()?;
+        let model_dir = Path::new(model_path).parent().unwrap_or(Path::new("."));
+        let path = model_dir.join("generation_config.json");
+        if !path.exists() {
+            return None;
+        }
+        println!("Loading generation config from: {:?}", path);
+        let data = std::fs::read_to_string(&path).ok()?;
+        serde_json::from_str(&data).ok()
+    }
+
+    /// Renders the conversation through the model's own chat template when
+    /// one is available, falling back to the old ad-hoc format otherwise.
+    /// `retrieved_context`, when present, is inserted as a system message
+    /// right before the final (current) turn.
+    fn render_prompt(&self, retrieved_context: Option<&str>) -> Result<String> {
+        let mut messages = self.conversation.clone();
+        if let Some(context) = retrieved_context {
+            let insert_at = messages.len().saturating_sub(1);
+            messages.insert(insert_at, ChatMessage {
+                role: "system".to_string(),
+                content: format!("Relevant context from earlier conversation:\n{}", context),
+            });
+        }
+
+        if let Some(template) = &self.chat_template {
+            let mut env = Environment::new();
+            env.add_template("chat", template)
+                .map_err(|e| anyhow!("Invalid chat template: {}", e))?;
+            let tmpl = env.get_template("chat")?;
+            let rendered_messages: Vec<_> = messages
+                .iter()
+                .map(|m| minijinja::context! { role => m.role.clone(), content => m.content.clone() })
+                .collect();
+            let rendered = tmpl.render(minijinja::context! {
+                messages => rendered_messages,
+                add_generation_prompt => true,
+            })?;
+            return Ok(rendered);
+        }
+
+        let mut prompt = String::new();
+        for msg in &messages {
+            match msg.role.as_str() {
+                "system" => prompt.push_str(&format!("System: {}\n", msg.content)),
+                "user" => prompt.push_str(&format!("User: {}\n", msg.content)),
+                "assistant" => prompt.push_str(&format!("Assistant: {}\n", msg.content)),
+                _ => {}
+            }
+        }
+        prompt.push_str("Assistant: ");
+        Ok(prompt)
+    }
+
+    /// Embeds `query` and pulls the top-k nearest indexed chunks, if any
+    /// embedding model and documents are available.
+    fn retrieve_context(&self, query: &str) -> Result<Option<String>> {
+        let Some(embedder) = &self.embedding_model else {
+            return Ok(None);
+        };
+        if self.document_store.is_empty() {
+            return Ok(None);
+        }
+
+        let query_embedding = embedder
+            .embed(vec![query.to_string()])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Embedding the query produced no output"))?;
+
+        let hits = self.document_store.top_k(&query_embedding, 3);
+        if hits.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(hits.join("\n\n")))
+        }
+    }
+
+    fn index_document(&mut self, text: String) -> Result<()> {
+        let embedder = self
+            .embedding_model
+            .as_ref()
+            .ok_or_else(|| anyhow!("No embedding model loaded - set EMBEDDING_MODEL_PATH/EMBEDDING_TOKENIZER_PATH/EMBEDDING_CONFIG_PATH"))?;
+        let embedding = embedder
+            .embed(vec![text.clone()])?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow!("Embedding the document produced no output"))?;
+        self.document_store.insert(text, embedding);
+        Ok(())
+    }
+
+    async fn send_message(&mut self, message: &str, app_handle: &AppHandle) -> Result<String> {
         if !self.is_initialized {
             return Err(anyhow!("Model not initialized"));
         }
 
+        let retrieved_context = self.retrieve_context(message)?;
+
         // Add user message to conversation
         self.conversation.push(ChatMessage {
             role: "user".to_string(),
@@ -167,81 +504,140 @@ impl LLMAgent {
         });
 
         // Generate response using the actual model
-        let response = self.generate_response_with_model(message).await?;
-        
+        let response = self.generate_response_with_model(message, retrieved_context, app_handle).await?;
+
         // Add assistant response to conversation
         self.conversation.push(ChatMessage {
             role: "assistant".to_string(),
             content: response.clone(),
         });
-        
+
         Ok(response)
     }
 
-    async fn generate_response_with_model(&mut self, _message: &str) -> Result<String> {
-        let model = self.model.as_ref().ok_or_else(|| anyhow!("Model not loaded"))?;
-        let tokenizer = self.tokenizer.as_ref().ok_or_else(|| anyhow!("Tokenizer not loaded"))?;
-        let cache = self.cache.as_mut().ok_or_else(|| anyhow!("Cache not initialized"))?;
-
-        // Build conversation context
-        let mut prompt = String::new();
-        for msg in &self.conversation {
-            match msg.role.as_str() {
-                "system" => prompt.push_str(&format!("System: {}\n", msg.content)),
-                "user" => prompt.push_str(&format!("User: {}\n", msg.content)),
-                "assistant" => prompt.push_str(&format!("Assistant: {}\n", msg.content)),
-                _ => {}
-            }
-        }
-        prompt.push_str("Assistant: ");
-
+    async fn generate_response_with_model(&mut self, _message: &str, retrieved_context: Option<String>, app_handle: &AppHandle) -> Result<String> {
+        // Rendered before borrowing `self.model`/`self.tokenizer` since it
+        // needs read access to the whole conversation.
+        let prompt = self.render_prompt(retrieved_context.as_deref())?;
         println!("Generating response for prompt: {}", prompt);
 
-        // Tokenize the prompt
+        let tokenizer = self.tokenizer.as_ref().ok_or_else(|| anyhow!("Tokenizer not loaded"))?;
+
+        // Tokenize the whole re-rendered prompt, then diff it against the
+        // token sequence the KV cache already holds (`cached_tokens`) to
+        // find how much of it is already cached. Since every turn only ever
+        // appends to the conversation, the new prompt's tokens normally
+        // share the old ones as a strict prefix - so only the new suffix
+        // (this turn's user message, generation prompt, etc.) needs to be
+        // forwarded, and the cache built up over the whole chat carries
+        // forward instead of being rebuilt from scratch every turn.
         let encoding = tokenizer.encode(prompt, false).map_err(|e| anyhow!("Tokenization failed: {}", e))?;
         let tokens = encoding.get_ids();
         println!("Tokenized to {} tokens", tokens.len());
-        
-        let input_tokens = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+
+        let common_prefix_len = tokens
+            .iter()
+            .zip(self.cached_tokens.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+
+        let common_prefix_len = if common_prefix_len < self.cached_tokens.len() {
+            // The new prompt diverges from what's cached (e.g. the
+            // conversation was edited/reset rather than just extended) -
+            // the cache can only grow, so the only way to recover is to
+            // reload the weights and reprocess from scratch.
+            println!("Prompt diverged from cached tokens at {}, reloading model to clear cache", common_prefix_len);
+            if let Some(model_path) = self.model_path.clone() {
+                self.model = Some(Self::load_quantized_model(&model_path, &self.device)?);
+            }
+            0
+        } else {
+            common_prefix_len
+        };
+        let model = self.model.as_mut().ok_or_else(|| anyhow!("Model not loaded"))?;
+        self.index_pos = common_prefix_len;
+        self.cached_tokens.truncate(common_prefix_len);
+
+        let new_tokens = &tokens[common_prefix_len..];
+        println!("Forwarding {} new prompt tokens ({} already cached)", new_tokens.len(), common_prefix_len);
+
+        let input_tokens = Tensor::new(new_tokens, &self.device)?.unsqueeze(0)?;
 
         // Generate response tokens
         let mut generated_tokens = Vec::new();
-        let mut current_tokens = input_tokens;
-        let max_new_tokens = 256;
+        let max_new_tokens = self.generation_config.max_new_tokens;
+        let mut token_stream = TokenOutputStream::new(tokenizer.clone());
+
+        let sampling = if self.generation_config.temperature <= 0. {
+            Sampling::ArgMax
+        } else {
+            Sampling::TopKThenTopP {
+                k: self.generation_config.top_k,
+                p: self.generation_config.top_p,
+                temperature: self.generation_config.temperature,
+            }
+        };
+        let mut logits_processor = LogitsProcessor::from_sampling(self.generation_config.seed, sampling);
+
+        // Process only the new suffix of the prompt in one forward pass,
+        // extending the model's KV cache from where the previous turn left
+        // it off, then only ever forward the single newest token from then
+        // on. This turns generation from O(n^2) (recomputing attention over
+        // the whole growing conversation every step) into O(n) overall, not
+        // just within a turn.
+        let logits = model.forward(&input_tokens, self.index_pos)?;
+        self.index_pos += input_tokens.dim(1)?;
+        self.cached_tokens.extend_from_slice(new_tokens);
+        // The quantized `ModelWeights::forward` impls already select the
+        // last position internally and return `(batch, vocab)`, not
+        // `(batch, seq, vocab)` - squeeze off the batch dim to get the
+        // `(vocab,)` logits vector `apply_repeat_penalty`/`sample` expect.
+        let mut last_token_logits = logits.squeeze(0)?;
 
         for i in 0..max_new_tokens {
             println!("Generation step {}", i);
-            
-            // Forward pass through model
-            let logits = model.forward(&current_tokens, 0, cache)?;
-            
-            // Get logits for the last token
-            let last_token_logits = logits.i((0, logits.dim(1)? - 1))?;
-            
-            // Simple greedy sampling - pick the token with highest probability
-            let next_token_id = last_token_logits.argmax(0)?.to_scalar::<u32>()?;
-            
-            // Check for end of sequence (common EOS tokens)
-            if next_token_id == 2 || next_token_id == 0 {
+
+            // Discourage recently-used tokens before sampling, to avoid
+            // degenerate repetition loops.
+            let penalized_logits = if self.generation_config.repeat_penalty == 1.0 {
+                last_token_logits.clone()
+            } else {
+                let start_at = generated_tokens
+                    .len()
+                    .saturating_sub(self.generation_config.repeat_last_n);
+                apply_repeat_penalty(
+                    &last_token_logits,
+                    self.generation_config.repeat_penalty,
+                    &generated_tokens[start_at..],
+                )?
+            };
+
+            let next_token_id = logits_processor.sample(&penalized_logits)?;
+
+            // Check for end of sequence, using the model's own EOS token(s)
+            if self.eos_token_ids.contains(&next_token_id) {
                 println!("Hit EOS token: {}", next_token_id);
                 break;
             }
-            
+
             generated_tokens.push(next_token_id);
-            
-            // Prepare next iteration - append the new token
-            let new_token = Tensor::new(&[next_token_id], &self.device)?.unsqueeze(0)?;
-            current_tokens = Tensor::cat(&[&current_tokens, &new_token], 1)?;
-            
-            // Stop if we've generated a reasonable amount
-            if generated_tokens.len() > 50 && generated_tokens.len() % 10 == 0 {
-                // Try to decode periodically to see if we have a complete thought
-                if let Ok(partial) = tokenizer.decode(&generated_tokens, true) {
-                    if partial.trim().ends_with('.') || partial.trim().ends_with('!') || partial.trim().ends_with('?') {
-                        break;
-                    }
-                }
+
+            // Stream the newly-decoded text to the frontend as soon as it is
+            // safe to split (i.e. it doesn't end mid-UTF-8-character).
+            if let Some(delta) = token_stream.next_token(next_token_id)? {
+                let _ = app_handle.emit("token", delta);
             }
+
+            if i + 1 == max_new_tokens {
+                break;
+            }
+
+            // Forward only the token we just generated, at its real position.
+            let new_token = Tensor::new(&[next_token_id], &self.device)?.unsqueeze(0)?;
+            let logits = model.forward(&new_token, self.index_pos)?;
+            self.index_pos += 1;
+            self.cached_tokens.push(next_token_id);
+            last_token_logits = logits.squeeze(0)?;
         }
 
         println!("Generated {} tokens", generated_tokens.len());
@@ -259,14 +655,23 @@ impl LLMAgent {
             role: "system".to_string(),
             content: self.system_prompt.clone(),
         });
-        
-        // Reset cache
-        if let Some(config) = &self.config {
-            self.cache = Some(Cache::new(true, DType::F32, config, &self.device)?);
+
+        // The quantized model keeps its KV cache inline rather than in a
+        // separate `Cache` value, so clearing it means reloading the weights.
+        if let Some(model_path) = &self.model_path {
+            self.model = Some(Self::load_quantized_model(model_path, &self.device)?);
         }
-        
+        self.index_pos = 0;
+        self.cached_tokens.clear();
+
         Ok(())
     }
+
+    fn load_quantized_model(model_path: &str, device: &Device) -> Result<LoadedModel> {
+        let mut file = std::fs::File::open(model_path)?;
+        let content = gguf_file::Content::read(&mut file)?;
+        LoadedModel::from_gguf(content, &mut file, device)
+    }
 }
 
 struct AppState {
@@ -280,9 +685,15 @@ async fn initialize_model(state: State<'_, AppState>) -> Result<String, String>
         // .env file not found, that's okay
     }
     
-    // Get model path from environment variable or use default
+    // Get model path from environment variable or use default.
+    //
+    // This intentionally points at a dense Qwen3 GGUF rather than the
+    // Qwen3-30B-A3B mixture-of-experts checkpoint: `LoadedModel::from_gguf`
+    // doesn't support `qwen3moe`/`phimoe` GGUFs (candle's quantized loaders
+    // for this family are dense-only), so an MoE default would make
+    // `initialize_model` fail out of the box with no `MODEL_PATH` set.
     let model_path = env::var("MODEL_PATH").unwrap_or_else(|_| {
-        r"E:\.lmstudio\models\lmstudio-community\Qwen3-30B-A3B-Instruct-2507-GGUF\Qwen3-30B-A3B-Instruct-2507-Q4_K_M.gguf".to_string()
+        r"E:\.lmstudio\models\lmstudio-community\Qwen3-8B-GGUF\Qwen3-8B-Q4_K_M.gguf".to_string()
     });
     
     // Clone the Arc to avoid holding the lock across await
@@ -299,13 +710,13 @@ async fn initialize_model(state: State<'_, AppState>) -> Result<String, String>
 }
 
 #[tauri::command]
-async fn send_message(message: String, state: State<'_, AppState>) -> Result<String, String> {
+async fn send_message(message: String, app_handle: AppHandle, state: State<'_, AppState>) -> Result<String, String> {
     // Clone the Arc to avoid holding the lock across await
     let agent_arc = state.llm_agent.clone();
-    
+
     let result = {
         let mut agent = agent_arc.lock().await;
-        agent.send_message(&message).await
+        agent.send_message(&message, &app_handle).await
     };
     
     match result {
@@ -325,17 +736,32 @@ async fn reset_conversation(state: State<'_, AppState>) -> Result<String, String
 }
 
 #[tauri::command]
-async fn update_system_prompt(_prompt: String, _state: State<'_, AppState>) -> Result<String, String> {
-    // This would require implementing system prompt update
+async fn update_system_prompt(prompt: String, state: State<'_, AppState>) -> Result<String, String> {
+    let mut agent = state.llm_agent.lock().await;
+    agent.update_system_prompt(prompt);
     Ok("System prompt updated".to_string())
 }
 
+#[tauri::command]
+async fn update_generation_config(config: GenerationConfig, state: State<'_, AppState>) -> Result<String, String> {
+    let mut agent = state.llm_agent.lock().await;
+    agent.update_generation_config(config);
+    Ok("Generation config updated".to_string())
+}
+
+#[tauri::command]
+async fn index_document(text: String, state: State<'_, AppState>) -> Result<String, String> {
+    let mut agent = state.llm_agent.lock().await;
+    agent.index_document(text).map_err(|e| format!("Failed to index document: {}", e))?;
+    Ok("Document indexed".to_string())
+}
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let app_state = AppState {
         llm_agent: Arc::new(Mutex::new(LLMAgent::new())),
     };
-    
+
     tauri::Builder::default()
         .plugin(tauri_plugin_opener::init())
         .manage(app_state)
@@ -343,8 +769,54 @@ pub fn run() {
             initialize_model,
             send_message,
             reset_conversation,
-            update_system_prompt
+            update_system_prompt,
+            update_generation_config,
+            index_document
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_output_stream_boundary_table() {
+        let cases = [
+            ("hello", "hello world", true),
+            ("hello", "hello", false),
+            ("hello", "hello \u{FFFD}", false),
+            ("", "", false),
+            ("a", "a\u{FFFD}", false),
+            ("", "a", true),
+        ];
+        for (prev, current, expected) in cases {
+            assert_eq!(
+                TokenOutputStream::is_new_text_ready(prev, current),
+                expected,
+                "is_new_text_ready({:?}, {:?})",
+                prev,
+                current
+            );
+        }
+    }
+
+    #[test]
+    fn token_value_to_string_handles_plain_and_added_token_forms() {
+        assert_eq!(token_value_to_string(&serde_json::json!("<|im_end|>")), Some("<|im_end|>".to_string()));
+        assert_eq!(
+            token_value_to_string(&serde_json::json!({"content": "<|im_end|>", "special": true})),
+            Some("<|im_end|>".to_string())
+        );
+        assert_eq!(token_value_to_string(&serde_json::json!(42)), None);
+        assert_eq!(token_value_to_string(&serde_json::json!(null)), None);
+    }
+
+    #[test]
+    fn json_value_to_u32s_handles_single_and_list_forms() {
+        assert_eq!(json_value_to_u32s(&serde_json::json!(2)), vec![2]);
+        assert_eq!(json_value_to_u32s(&serde_json::json!([2, 151645])), vec![2, 151645]);
+        assert_eq!(json_value_to_u32s(&serde_json::json!("not a number")), Vec::<u32>::new());
+    }
 }
\ No newline at end of file
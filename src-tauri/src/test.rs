@@ -2,10 +2,19 @@ use anyhow::{anyhow, Result};
 use candle_core::quantized::gguf_file;
 use candle_core::safetensors::{self, SafeTensors};
 use candle_core::{Device, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
 use candle_transformers::models::llama::{Config, Llama};
+use candle_transformers::utils::apply_repeat_penalty;
 use std::{fs::File, path::Path};
 use tokenizers::Tokenizer;
 
+const TEMPERATURE: f64 = 0.8;
+const TOP_P: f64 = 0.9;
+const TOP_K: usize = 40;
+const SEED: u64 = 299792458;
+const REPEAT_PENALTY: f32 = 1.1;
+const REPEAT_LAST_N: usize = 64;
+
 fn main() -> Result<()> {
     let model_path = "/Users/agus/.lmstudio/models/lmstudio-community/DeepSeek-R1-0528-Qwen3-8B-MLX-4bit/model.safetensors";
     let tokenizer_path = "/Users/agus/.lmstudio/models/lmstudio-community/DeepSeek-R1-0528-Qwen3-8B-MLX-4bit/tokenizer.json";
@@ -23,12 +32,21 @@ fn main() -> Result<()> {
     let mut output_ids = tokens.get_ids().to_vec();
     let mut cache = model.empty_cache();
 
+    let sampling = if TEMPERATURE <= 0. {
+        Sampling::ArgMax
+    } else {
+        Sampling::TopKThenTopP { k: TOP_K, p: TOP_P, temperature: TEMPERATURE }
+    };
+    let mut logits_processor = LogitsProcessor::from_sampling(SEED, sampling);
+
     // Generate up to 128 tokens
     for _ in 0..128 {
         let input_tensor = Tensor::new(output_ids.as_slice(), &device)?.unsqueeze(0)?;
         let logits = model.forward(&input_tensor, 0, &mut cache)?;
         let logits = logits.squeeze(0)?.get(output_ids.len() - 1)?;
-        let next_token_id = logits.argmax(0)?.to_scalar::<u32>()?;
+        let start_at = output_ids.len().saturating_sub(REPEAT_LAST_N);
+        let logits = apply_repeat_penalty(&logits, REPEAT_PENALTY, &output_ids[start_at..])?;
+        let next_token_id = logits_processor.sample(&logits)?;
 
         // Stop on EOS token
         if next_token_id == tokenizer.token_to_id("</s>").unwrap_or(0) {
@@ -54,6 +72,17 @@ fn load_model<P: AsRef<Path>>(path: P, device: &Device) -> Result<(Llama, Config
             println!("Detected GGUF model format.");
             let mut file = File::open(path_ref)?;
             let gguf = gguf_file::Content::read(&mut file)?;
+            // Qwen/DeepSeek-R1-Qwen GGUFs report their own architecture here;
+            // this example only exercises the Llama path.
+            let architecture = gguf
+                .metadata
+                .get("general.architecture")
+                .and_then(|v| v.to_string().ok())
+                .map(|s| s.to_string())
+                .unwrap_or_else(|| "llama".to_string());
+            if architecture != "llama" {
+                println!("Warning: architecture '{}' is not Llama, loading it as Llama anyway.", architecture);
+            }
             let config = Config::from_gguf(&gguf)?;
             let vb = gguf.var_builder(device)?;
             let model = Llama::load(vb, &config)?;